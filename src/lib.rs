@@ -1,78 +1,382 @@
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io::{self, Read, Seek, Write},
     path::Path,
 };
 
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
 const BUFFER_SIZE: usize = 8 * 1024;
 
 /// Copy contents of source to dest, only writing to dest once differing bytes are encountered.
 ///
 /// Will result in source and est having identical bytes while trying to avoid unnecessary writes.
-/// If differing bytes are encountered, then `io::copy` will be used to write the remaining bytes to dest.
-pub fn copy<R: io::Read, P: AsRef<Path>>(mut source: R, dest: P) -> io::Result<u64> {
+/// Equivalent to `CopyOptions::default().copy(source, dest)` — see [`CopyOptions`] to control
+/// whether comparison stops at the first mismatch. A thin wrapper over opening `dest` as a
+/// [`File`]; see [`copy_into`] to write the same lazy diff into any [`LazyTarget`] instead of a
+/// filesystem path.
+pub fn copy<R: io::Read, P: AsRef<Path>>(source: R, dest: P) -> io::Result<u64> {
+    CopyOptions::default().copy(source, dest)
+}
+
+/// Options controlling how [`copy`] compares source and dest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOptions {
+    early_exit: bool,
+}
+
+impl CopyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Once a differing or short block is found, stop comparing and write the remainder of
+    /// `source` unconditionally, instead of continuing to compare block-by-block to the end of
+    /// `source`.
+    ///
+    /// Off by default: continuing the comparison avoids rewriting any later blocks of `dest`
+    /// that already match, at the cost of always reading the rest of `dest`. Turn this on when
+    /// the caller knows the tail of `dest` always differs, to skip those reads.
+    pub fn early_exit(mut self, early_exit: bool) -> Self {
+        self.early_exit = early_exit;
+        self
+    }
+
+    pub fn copy<R: io::Read, P: AsRef<Path>>(&self, source: R, dest: P) -> io::Result<u64> {
+        let mut dest = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dest)?;
+        copy_with_tail(source, &mut dest, self.early_exit, seek_tail_copy)
+    }
+}
+
+/// Hand the remainder of `source` to `dest`, writing it at successive offsets. This is the
+/// portable tail-copy strategy used by [`CopyOptions::copy`] and [`copy_into`] for any
+/// `T: LazyTarget`.
+fn seek_tail_copy<R: Read, T: LazyTarget>(
+    source: &mut R,
+    dest: &mut T,
+    offset: u64,
+) -> io::Result<u64> {
+    let mut buf = [0; BUFFER_SIZE];
+    let mut copied = 0u64;
+    loop {
+        let n = source.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        dest.write_at(&buf[..n], offset + copied)?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// Like [`copy`], but when a differing block hands the remainder off to be written in bulk, the
+/// transfer happens entirely in the kernel via `copy_file_range` (falling back to `sendfile`,
+/// then a plain userspace copy) instead of bouncing bytes through a userspace buffer.
+///
+/// This always compares with [`CopyOptions::early_exit`] set, since the kernel-accelerated path
+/// only pays off once we've committed to writing the rest of the file unconditionally.
+pub fn copy_file<P: AsRef<Path>>(src: P, dest: P) -> io::Result<u64> {
+    let source = File::open(src)?;
     let mut dest = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .open(dest)?;
+    copy_with_tail(source, &mut dest, true, kernel_tail_copy)
+}
+
+#[cfg(target_os = "linux")]
+fn kernel_tail_copy(source: &mut File, dest: &mut File, offset: u64) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = source.metadata()?.len().saturating_sub(offset);
+    let src_fd = source.as_raw_fd();
+    let dest_fd = dest.as_raw_fd();
+    let mut src_offset = offset as libc::loff_t;
+    let mut dest_offset = offset as libc::loff_t;
+    let mut remaining = len;
+    let mut copied: u64 = 0;
+
+    while remaining > 0 {
+        let chunk = remaining.min(usize::MAX as u64) as usize;
+        // SAFETY: src_fd/dest_fd are valid, open file descriptors for the lifetime of this call,
+        // and src_offset/dest_offset point at valid, appropriately-sized `loff_t`s.
+        let ret = unsafe {
+            libc::copy_file_range(
+                src_fd,
+                &mut src_offset,
+                dest_fd,
+                &mut dest_offset,
+                chunk,
+                0,
+            )
+        };
+        match ret {
+            -1 => {
+                let err = io::Error::last_os_error();
+                return match (err.raw_os_error(), copied) {
+                    (Some(libc::ENOSYS), 0) | (Some(libc::EXDEV), 0) => {
+                        sendfile_tail_copy(source, dest, offset + copied)
+                    }
+                    _ => Err(err),
+                };
+            }
+            0 => break,
+            n => {
+                copied += n as u64;
+                remaining -= n as u64;
+            }
+        }
+    }
+
+    Ok(copied)
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile_tail_copy(source: &mut File, dest: &mut File, offset: u64) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = source.metadata()?.len().saturating_sub(offset);
+    let src_fd = source.as_raw_fd();
+    let dest_fd = dest.as_raw_fd();
+    dest.seek(io::SeekFrom::Start(offset))?;
+    let mut src_offset = offset as libc::off_t;
+    let mut remaining = len;
+    let mut copied: u64 = 0;
+
+    while remaining > 0 {
+        let chunk = remaining.min(usize::MAX as u64) as usize;
+        // SAFETY: src_fd/dest_fd are valid, open file descriptors for the lifetime of this call,
+        // dest_fd's offset was just seeked to `offset + copied`, and src_offset points at a
+        // valid, appropriately-sized `off_t`.
+        let ret = unsafe { libc::sendfile(dest_fd, src_fd, &mut src_offset, chunk) };
+        match ret {
+            -1 => {
+                let err = io::Error::last_os_error();
+                return match (err.raw_os_error(), copied) {
+                    (Some(libc::ENOSYS), 0) => seek_tail_copy(source, dest, offset),
+                    _ => Err(err),
+                };
+            }
+            0 => break,
+            n => {
+                copied += n as u64;
+                remaining -= n as u64;
+            }
+        }
+    }
+
+    Ok(copied)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_tail_copy(source: &mut File, dest: &mut File, offset: u64) -> io::Result<u64> {
+    seek_tail_copy(source, dest, offset)
+}
+
+/// A truncatable store [`copy_into`] can write a lazy diff into, addressed by explicit byte
+/// offset so the core compare-and-write loop in [`copy_with_tail`] never needs to track (or
+/// restore) a separate read/write cursor.
+///
+/// Implemented for [`File`] via the platform's positional I/O (see [`read_at`]/[`write_at`]), so
+/// the destination doesn't have to be a filesystem path at all — an in-memory `Cursor<Vec<u8>>`,
+/// an embedded flash driver, or a region-constrained view of a larger store can all implement
+/// this instead. That's exactly where "only write differing bytes" matters most: flash with
+/// limited write cycles, or stores where opening a path isn't possible.
+pub trait LazyTarget {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl LazyTarget for File {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        read_at(self, buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        write_at(self, buf, offset)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+impl LazyTarget for io::Cursor<Vec<u8>> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        Read::read(self, buf)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        Write::write_all(self, buf)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+/// Like [`copy`], but writes the lazy diff into any [`LazyTarget`] instead of opening a
+/// filesystem path.
+pub fn copy_into<R: Read, T: LazyTarget>(source: R, dest: &mut T) -> io::Result<u64> {
+    copy_with_tail(source, dest, false, seek_tail_copy)
+}
+
+/// Run the block-by-block lazy copy loop shared by [`copy`]/[`copy_file`] (over a [`File`],
+/// accelerated with positional I/O and, for `copy_file`, a kernel-side tail copy) and
+/// [`copy_into`] (over any [`LazyTarget`]). Hands the remainder of `source` to `tail_copy`
+/// whenever a mismatched or short block is found and `early_exit` is set.
+fn copy_with_tail<R: Read, T: LazyTarget>(
+    mut source: R,
+    dest: &mut T,
+    early_exit: bool,
+    tail_copy: fn(&mut R, &mut T, u64) -> io::Result<u64>,
+) -> io::Result<u64> {
     let mut source_buffer = [0; BUFFER_SIZE];
     let mut dest_buffer = [0; BUFFER_SIZE];
     let mut bytes_copied = 0;
+    let mut offset: u64 = 0;
     loop {
         let source_bytes_read = source.read(&mut source_buffer)?;
         if source_bytes_read == 0 {
             break;
         }
-        let dest_bytes_read = dest.read(&mut dest_buffer)?;
+        let dest_bytes_read = dest.read_at(&mut dest_buffer, offset)?;
         match dest_bytes_read.cmp(&source_bytes_read) {
             std::cmp::Ordering::Equal
                 if source_buffer[..source_bytes_read] == dest_buffer[..dest_bytes_read] =>
             {
                 bytes_copied += source_bytes_read as u64;
+                offset += source_bytes_read as u64;
             }
             // Content differs
             std::cmp::Ordering::Equal => {
-                // Move backwards and write the latest read
-                dest.seek(io::SeekFrom::Current(-(dest_bytes_read as i64)))?;
-                dest.write_all(&source_buffer[..source_bytes_read])?;
+                let d = first_difference(
+                    &source_buffer[..source_bytes_read],
+                    &dest_buffer[..dest_bytes_read],
+                )
+                .unwrap_or(0);
+                dest.write_at(&source_buffer[d..source_bytes_read], offset + d as u64)?;
                 bytes_copied += source_bytes_read as u64;
-                // Use `io::copy` to write rest of bytes to file
-                let copied = io::copy(&mut source, &mut dest)?;
-                bytes_copied += copied;
-                break;
+                offset += source_bytes_read as u64;
+                if early_exit {
+                    bytes_copied += tail_copy(&mut source, dest, offset)?;
+                    break;
+                }
             }
             // dest has more bytes than source
             std::cmp::Ordering::Greater => {
-                // Move backward and write the latest read
-                dest.seek(io::SeekFrom::Current(-(dest_bytes_read as i64)))?;
-                dest.write_all(&source_buffer[..source_bytes_read])?;
+                dest.write_at(&source_buffer[..source_bytes_read], offset)?;
                 bytes_copied += source_bytes_read as u64;
-                break;
+                offset += source_bytes_read as u64;
+                if early_exit {
+                    bytes_copied += tail_copy(&mut source, dest, offset)?;
+                    break;
+                }
             }
             // source has more bytes than dest
             std::cmp::Ordering::Less => {
-                // Move backward and write the latest read
-                dest.seek(io::SeekFrom::Current(-(dest_bytes_read as i64)))?;
-                dest.write_all(&source_buffer[..source_bytes_read])?;
+                dest.write_at(&source_buffer[..source_bytes_read], offset)?;
                 bytes_copied += source_bytes_read as u64;
-                // Use `io::copy` to write rest of bytes to file
-                let copied = io::copy(&mut source, &mut dest)?;
-                bytes_copied += copied;
-                break;
+                offset += source_bytes_read as u64;
+                if early_exit {
+                    bytes_copied += tail_copy(&mut source, dest, offset)?;
+                    break;
+                }
             }
         }
     }
 
     // Possibly truncate dest to be the same size as source
-    dest.set_len(bytes_copied)?;
+    dest.truncate(bytes_copied)?;
 
     Ok(bytes_copied)
 }
 
+/// Find the index of the first byte at which `a` and `b` differ, comparing a `usize`
+/// (8 bytes on most platforms) at a time rather than byte-by-byte.
+///
+/// Returns `None` if the slices are equal over their shared length.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    const WORD_SIZE: usize = std::mem::size_of::<usize>();
+    let len = a.len().min(b.len());
+    let mut i = 0;
+    while i + WORD_SIZE <= len {
+        let a_word = usize::from_ne_bytes(a[i..i + WORD_SIZE].try_into().unwrap());
+        let b_word = usize::from_ne_bytes(b[i..i + WORD_SIZE].try_into().unwrap());
+        let diff = a_word ^ b_word;
+        if diff != 0 {
+            let byte_in_word = if cfg!(target_endian = "little") {
+                diff.trailing_zeros() / 8
+            } else {
+                diff.leading_zeros() / 8
+            };
+            return Some(i + byte_in_word as usize);
+        }
+        i += WORD_SIZE;
+    }
+    // Trailing remainder shorter than a word, compared byte-by-byte.
+    a[i..len]
+        .iter()
+        .zip(&b[i..len])
+        .position(|(x, y)| x != y)
+        .map(|pos| i + pos)
+}
+
+/// Read from `file` at `offset` without disturbing a separately-tracked cursor.
+#[cfg(unix)]
+fn read_at(file: &mut File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    FileExt::read_at(file, buf, offset)
+}
+
+/// Write all of `buf` to `file` at `offset` without disturbing a separately-tracked cursor.
+#[cfg(unix)]
+fn write_at(file: &mut File, buf: &[u8], offset: u64) -> io::Result<()> {
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &mut File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek_read(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &mut File, buf: &[u8], offset: u64) -> io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        written += file.seek_write(&buf[written..], offset + written as u64)?;
+    }
+    Ok(())
+}
+
+// Platforms without positional file I/O fall back to the old seek-then-read/write dance.
+#[cfg(not(any(unix, windows)))]
+fn read_at(file: &mut File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    Seek::seek(file, io::SeekFrom::Start(offset))?;
+    Read::read(file, buf)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_at(file: &mut File, buf: &[u8], offset: u64) -> io::Result<()> {
+    Seek::seek(file, io::SeekFrom::Start(offset))?;
+    Write::write_all(file, buf)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
+    use std::io::{self, Write};
 
     use super::*;
 
@@ -182,4 +486,157 @@ mod tests {
         assert_eq!(&wanted[..], &on_disk);
         Ok(())
     }
+
+    #[test]
+    fn early_exit_partial_match() -> io::Result<()> {
+        let tmp_dir = TempDir::new("early-exit")?;
+        let output = tmp_dir.path().join("bar.txt");
+        let wanted = b"foo\nbar\nbaz\n";
+        File::create(&output)?.write_all(b"foo\nFEZ\nbaz\n")?;
+        let bytes_copied = CopyOptions::new().early_exit(true).copy(&wanted[..], &output)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        let on_disk = std::fs::read(&output)?;
+        assert_eq!(&wanted[..], &on_disk);
+        Ok(())
+    }
+
+    /// A [`LazyTarget`] backed by an in-memory buffer that records the offset and length of
+    /// every `write_at` call, so tests can assert on *which* blocks were rewritten rather than
+    /// just the final bytes.
+    #[derive(Default)]
+    struct RecordingTarget {
+        data: Vec<u8>,
+        writes: Vec<(u64, usize)>,
+    }
+
+    impl LazyTarget for RecordingTarget {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let offset = offset as usize;
+            if offset >= self.data.len() {
+                return Ok(0);
+            }
+            let n = (self.data.len() - offset).min(buf.len());
+            buf[..n].copy_from_slice(&self.data[offset..offset + n]);
+            Ok(n)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let start = offset as usize;
+            if self.data.len() < start + buf.len() {
+                self.data.resize(start + buf.len(), 0);
+            }
+            self.data[start..start + buf.len()].copy_from_slice(buf);
+            self.writes.push((offset, buf.len()));
+            Ok(())
+        }
+
+        fn truncate(&mut self, len: u64) -> io::Result<()> {
+            self.data.truncate(len as usize);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn default_mode_skips_matching_later_block() -> io::Result<()> {
+        let first_block = vec![b'a'; BUFFER_SIZE];
+        let second_block = vec![b'b'; BUFFER_SIZE];
+        let mut wanted = first_block.clone();
+        wanted.extend_from_slice(&second_block);
+
+        let mut dest_data = vec![b'z'; BUFFER_SIZE];
+        dest_data.extend_from_slice(&second_block);
+        let mut dest = RecordingTarget {
+            data: dest_data,
+            writes: Vec::new(),
+        };
+
+        let bytes_copied = copy_with_tail(&wanted[..], &mut dest, false, seek_tail_copy)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        assert_eq!(dest.data, wanted);
+        // Only the differing first block was rewritten; the already-matching second block was
+        // left alone.
+        assert_eq!(dest.writes, vec![(0, BUFFER_SIZE)]);
+        Ok(())
+    }
+
+    #[test]
+    fn early_exit_rewrites_matching_later_block() -> io::Result<()> {
+        let first_block = vec![b'a'; BUFFER_SIZE];
+        let second_block = vec![b'b'; BUFFER_SIZE];
+        let mut wanted = first_block.clone();
+        wanted.extend_from_slice(&second_block);
+
+        let mut dest_data = vec![b'z'; BUFFER_SIZE];
+        dest_data.extend_from_slice(&second_block);
+        let mut dest = RecordingTarget {
+            data: dest_data,
+            writes: Vec::new(),
+        };
+
+        let bytes_copied = copy_with_tail(&wanted[..], &mut dest, true, seek_tail_copy)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        assert_eq!(dest.data, wanted);
+        // early_exit bails to the tail copy after the first mismatch, rewriting the second block
+        // even though it already matched.
+        assert_eq!(dest.writes, vec![(0, BUFFER_SIZE), (BUFFER_SIZE as u64, BUFFER_SIZE)]);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_file_matches_copy() -> io::Result<()> {
+        let tmp_dir = TempDir::new("copy-file")?;
+        let source = tmp_dir.path().join("foo.txt");
+        let output = tmp_dir.path().join("bar.txt");
+        let wanted = b"foo\nbar\nbaz\n";
+        File::create(&source)?.write_all(wanted)?;
+        File::create(&output)?.write_all(b"foo\nFEZ\nbaz\n")?;
+        let bytes_copied = copy_file(&source, &output)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        let on_disk = std::fs::read(&output)?;
+        assert_eq!(&wanted[..], &on_disk);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_file_kernel_tail_copies_non_zero_remainder() -> io::Result<()> {
+        let tmp_dir = TempDir::new("copy-file-large")?;
+        let source_path = tmp_dir.path().join("foo.bin");
+        let output = tmp_dir.path().join("bar.bin");
+
+        // First block differs, so `copy_file` hands the rest (half a block) to
+        // `kernel_tail_copy` with a non-zero length, actually exercising `copy_file_range`.
+        let mut wanted = vec![b'a'; BUFFER_SIZE];
+        wanted.extend(vec![b'b'; BUFFER_SIZE / 2]);
+        let mut existing = vec![b'z'; BUFFER_SIZE];
+        existing.extend(vec![b'b'; BUFFER_SIZE / 2]);
+
+        File::create(&source_path)?.write_all(&wanted)?;
+        File::create(&output)?.write_all(&existing)?;
+
+        let bytes_copied = copy_file(&source_path, &output)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        let on_disk = std::fs::read(&output)?;
+        assert_eq!(wanted, on_disk);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_into_cursor_full_match() -> io::Result<()> {
+        let wanted = b"foo\nbar\n";
+        let mut dest = io::Cursor::new(wanted.to_vec());
+        let bytes_copied = copy_into(&wanted[..], &mut dest)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        assert_eq!(&wanted[..], dest.get_ref().as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_into_cursor_partial_match() -> io::Result<()> {
+        let wanted = b"foo\nbar\nbaz\n";
+        let mut dest = io::Cursor::new(b"foo\nFEZ\nbaz\n".to_vec());
+        let bytes_copied = copy_into(&wanted[..], &mut dest)?;
+        assert_eq!(bytes_copied, wanted.len() as u64);
+        assert_eq!(&wanted[..], dest.get_ref().as_slice());
+        Ok(())
+    }
 }